@@ -1,131 +1,212 @@
-use hyper::{Client, Server};
+use hyper::service::Service;
 use lambda_http::{
     handler,
     lambda::{self, Context},
-    Body, IntoResponse, Request, RequestExt, Response,
+    Body, IntoResponse, Request, RequestContext, RequestExt, Response,
 };
-use rand::Rng;
-use routerify::{Router, RouterService};
+use routerify::{RequestService, RequestServiceBuilder, Router};
 use std::convert::Infallible;
-use std::{net::SocketAddr, str::FromStr};
-use tokio::sync::oneshot;
+use std::future::poll_fn;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use tokio::sync::OnceCell;
 use url;
 
-const SERVER_ADDR: &str = "127.0.0.1:8080";
-
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     lambda::run(handler(start)).await?;
     Ok(())
 }
 
+// NOTE on response streaming (RESPONSE_STREAM invoke mode, tracked as request chunk0-2):
+// deliberately not implemented against this file's current dependency generation. Streaming a
+// `Response<Body>` to the Lambda runtime as trailer-terminated chunks (`streaming::Body`,
+// `LambdaEvent`) only exists in the `lambda_runtime` 0.8+ rewrite, which is a breaking,
+// incompatible API from the `handler()`/`Context`/`Body::Text`/`Body::Binary` API the rest of
+// this file is built on — the two can't coexist in one dependency resolution, feature-gated or
+// not. There's also no `Cargo.toml` in this tree to pin a version or declare a `streaming`
+// feature against. Picking up streaming support means migrating this whole file across that
+// breaking boundary (buffered path included) once a manifest exists, not adding it piecemeal;
+// deferred until that migration happens.
 type Error = Box<dyn std::error::Error + Sync + Send + 'static>;
 
-async fn start(req: lambda_http::Request, _ctx: Context) -> Result<impl IntoResponse, Error> {
+/// `RequestServiceBuilder` owns the route table and router state, so it's expensive enough to
+/// build that we only want to pay for it once per Lambda execution environment and reuse it
+/// across warm invocations instead of rebuilding it on every request. This is the cold-start
+/// initialization phase; `request_service` below is the only thing that runs per request.
+static REQUEST_SERVICE_BUILDER: OnceCell<RequestServiceBuilder<Body, Infallible>> = OnceCell::const_new();
+
+/// One-time cold-start initialization: build the app state and the router it's wired into. Called
+/// at most once per execution environment, from `REQUEST_SERVICE_BUILDER.get_or_init`.
+fn init_router() -> RequestServiceBuilder<Body, Infallible> {
+    RequestServiceBuilder::new(router(State::default())).unwrap()
+}
+
+/// Produce the per-request service Routerify normally hands out per-connection, without going
+/// through an actual TCP accept loop. The expensive part — building the router and its state — is
+/// warmed up at most once per execution environment; `RequestServiceBuilder::build` just wraps the
+/// shared router in a cheap per-request handle.
+async fn request_service() -> RequestService<Body, Infallible> {
+    let builder = REQUEST_SERVICE_BUILDER.get_or_init(|| async { init_router() }).await;
+    // Routerify keys a `RequestService` by the connecting peer's address for things like
+    // `req.remote_addr()`. Lambda invocations have no real TCP peer, so a loopback placeholder
+    // stands in for one.
+    let remote_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    builder.build(remote_addr)
+}
+
+/// Run a Lambda request through the Routerify router and return the raw hyper response.
+async fn route(req: lambda_http::Request, ctx: Context) -> Result<hyper::Response<hyper::Body>, Error> {
     // Store a copy of the query parameters, since AWS Lambda parsed these already.
     let query_params = req.query_string_parameters();
+    // The API Gateway request context (stage, authorizer claims, requestId, identity, ...) is only
+    // reachable through `RequestExt` on the `lambda_http::Request`, so it has to be captured before
+    // the request is converted into a bare hyper request below.
+    let apigw_context = req.request_context();
     // Convert the lambda_http::Request into a hyper::Request.
     let (mut parts, body) = req.into_parts();
+    // Make the Lambda invocation `Context` and the API Gateway request context available to
+    // Routerify handlers through `RouterRequestExt`, since they're otherwise thrown away here.
+    parts.extensions.insert(ctx);
+    parts.extensions.insert(apigw_context);
     let body = match body {
         lambda_http::Body::Empty => hyper::Body::empty(),
         lambda_http::Body::Text(t) => hyper::Body::from(t.into_bytes()),
         lambda_http::Body::Binary(b) => hyper::Body::from(b),
     };
-    // Prefix the local Routerify server's address to the path of the incoming Lambda request.
-    let mut uri = format!("http://{}{}", SERVER_ADDR, parts.uri.path());
     // AWS Lambda Rust Runtime will automatically parse the query params *and* remove those
     // query parameters from the original URI. This is fine if you're writing your logic directly
     // in the handler function, but for passing-through to a separate router library, we need to
-    // re-url-encode the query parameters and place them back into the URI.
+    // re-url-encode the query parameters and place them back onto the path.
+    //
+    // `QueryMap::iter` only yields the first value for a repeated key (e.g. `?tag=a&tag=b`), so
+    // every value has to be pulled explicitly through `QueryMap::all` to round-trip API Gateway's
+    // `multiValueQueryStringParameters` faithfully.
+    let mut uri = parts.uri.path().to_string();
     if !query_params.is_empty() {
         uri += "?";
-        // Create a peekable iterator over the query parameters. This is used to add "&" in between
-        // each of the query parameters, but prevents adding an extraneous "&" at the end of the
-        // query parameter string.
-        let mut params = query_params.iter().peekable();
-        while let Some((key, value)) = params.next() {
+        let mut seen_keys = std::collections::HashSet::new();
+        let mut pairs = query_params
+            .iter()
+            .filter(|(key, _)| seen_keys.insert(key.to_string()))
+            .flat_map(|(key, _)| {
+                query_params
+                    .all(key)
+                    .into_iter()
+                    .flatten()
+                    .map(move |value| (key, value))
+            })
+            .peekable();
+        while let Some((key, value)) = pairs.next() {
             uri += url::form_urlencoded::Serializer::new(String::new())
                 .append_pair(key, value)
                 .finish()
                 .as_str();
             // If this is not the last parameter, append a "&" for the next parameter...
-            if params.peek().is_some() {
+            if pairs.peek().is_some() {
                 uri += "&";
             }
         }
     }
-    parts.uri = match hyper::Uri::from_str(uri.as_str()) {
-        Ok(uri) => uri,
-        Err(e) => panic!(format!("failed to build uri: {:?}", e)),
-    };
+    parts.uri = hyper::Uri::from_str(uri.as_str())?;
+    // Unlike the query string, headers don't need a manual rebuild here: API Gateway's
+    // `multiValueHeaders` is already a superset of `headers` (it carries every value for every
+    // key, single or repeated), and `lambda_http` builds `parts.headers` from `multiValueHeaders`
+    // when the event provides it, falling back to the single-valued `headers` map only for
+    // integrations that don't send multi-value headers at all (e.g. an ALB target group without
+    // multi-value mode enabled). So a repeated request header such as two `Cookie` entries is
+    // already present in `parts.headers`, which is a `HeaderMap` and natively supports repeats.
     let req = hyper::Request::from_parts(parts, body);
 
-    // Generate some random state and build the HTTP router.
-    let router = router(State{ count: rand::thread_rng().gen::<u8>() });
-    // Start a internal Routerify server with the above router.
-    let serve = serve(router).await;
-    // Send the request to the routerify server and return the response.
-    let resp = Client::new().request(req).await.unwrap();
-    // Shutdown the Routerify server.
-    serve.shutdown();
+    // Drive the Routerify service directly instead of round-tripping the request through a
+    // loopback TCP server.
+    let mut service = request_service().await;
+    poll_fn(|cx| service.poll_ready(cx)).await?;
+    Ok(service.call(req).await?)
+}
 
+async fn start(req: lambda_http::Request, ctx: Context) -> Result<impl IntoResponse, Error> {
+    let resp = route(req, ctx).await?;
     // Convert the hyper::Response into a lambda_http::Response.
     let (parts, body) = resp.into_parts();
     let body_bytes = hyper::body::to_bytes(body).await?;
-    let body = String::from_utf8(body_bytes.to_vec()).unwrap();
-    Ok(lambda_http::Response::from_parts(parts, lambda_http::Body::from(body)))
-}
-
-struct State {
-    count: u8
-}
-
-fn router(state: State) -> Router<Body, Infallible> {
-    Router::builder().data(state).get("/data", get_count).build().unwrap()
+    let body = into_lambda_body(&parts, body_bytes);
+    Ok(lambda_http::Response::from_parts(parts, body))
 }
 
-async fn get_count(req: Request<Body>) -> Result<Response<Body>, Infallible> {
-    // Access the app state.
-    let state = req.data::<State>().unwrap();
-    Ok(Response::new(Body::from(format!("Count: {}", state.count))))
+/// Convert a raw response body into the `lambda_http::Body` variant API Gateway expects,
+/// preserving binary payloads (images, gzip, protobuf, ...) instead of assuming everything is
+/// UTF-8 text.
+fn into_lambda_body(parts: &hyper::http::response::Parts, bytes: hyper::body::Bytes) -> lambda_http::Body {
+    let declares_binary = parts.headers.contains_key(hyper::header::CONTENT_ENCODING)
+        || parts
+            .headers
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|content_type| {
+                !(content_type.starts_with("text/")
+                    || content_type.contains("json")
+                    || content_type.contains("xml")
+                    || content_type.contains("javascript"))
+            })
+            .unwrap_or(false);
+
+    if !declares_binary {
+        if let Ok(text) = String::from_utf8(bytes.to_vec()) {
+            return lambda_http::Body::Text(text);
+        }
+    }
+    lambda_http::Body::Binary(bytes.to_vec())
 }
 
-pub struct Serve {
-    addr: SocketAddr,
-    tx: oneshot::Sender<()>,
+/// Mirrors `lambda_http::RequestExt`, giving Routerify handlers ergonomic access to the Lambda
+/// invocation `Context` and the API Gateway request context that `route` stashes in the request
+/// extensions before handing the request to the router.
+trait RouterRequestExt {
+    fn lambda_context(&self) -> &Context;
+    fn apigw_request_context(&self) -> &RequestContext;
 }
 
-impl Serve {
-    pub fn addr(&self) -> SocketAddr {
-        self.addr
+impl<B> RouterRequestExt for Request<B> {
+    fn lambda_context(&self) -> &Context {
+        self.extensions()
+            .get::<Context>()
+            .expect("lambda Context missing from request extensions")
     }
 
-    pub fn shutdown(self) {
-        self.tx.send(()).unwrap();
+    fn apigw_request_context(&self) -> &RequestContext {
+        self.extensions()
+            .get::<RequestContext>()
+            .expect("API Gateway request context missing from request extensions")
     }
 }
 
-pub async fn serve<B, E>(router: Router<B, E>) -> Serve
-    where
-        B: hyper::body::HttpBody + Send + Sync + Unpin + 'static,
-        E: std::error::Error + Send + Sync + Unpin + 'static,
-        <B as hyper::body::HttpBody>::Data: Send + Sync + 'static,
-        <B as hyper::body::HttpBody>::Error: std::error::Error + Send + Sync + 'static,
-{
-    let service = RouterService::new(router).unwrap();
-    let server = Server::bind(&SocketAddr::from_str(SERVER_ADDR).unwrap()).serve(service);
-    let addr = server.local_addr();
-
-    let (tx, rx) = oneshot::channel::<()>();
-
-    let graceful_server = server.with_graceful_shutdown(async {
-        rx.await.unwrap();
-    });
-
-    tokio::spawn(async move {
-        graceful_server.await.unwrap();
-    });
+/// App state, built once at cold start by `init_router` and shared across every warm invocation
+/// for the lifetime of the execution environment. `invocation_count` demonstrates that this is the
+/// same `State` on every request, not a fresh one: a real handler would hold a DB pool, a cache, or
+/// config here instead.
+#[derive(Default)]
+struct State {
+    invocation_count: std::sync::atomic::AtomicU64,
+}
 
-    Serve { addr, tx }
+fn router(state: State) -> Router<Body, Infallible> {
+    Router::builder().data(state).get("/data", get_count).build().unwrap()
 }
 
+async fn get_count(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    // Access the app state, the Lambda invocation context, and the API Gateway request context.
+    let state = req.data::<State>().unwrap();
+    let count = state.invocation_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+    let request_id = &req.lambda_context().request_id;
+    let stage = match req.apigw_request_context() {
+        RequestContext::ApiGatewayV1(ctx) => ctx.stage.as_deref(),
+        RequestContext::ApiGatewayV2(ctx) => ctx.stage.as_deref(),
+        _ => None,
+    }
+    .unwrap_or("n/a");
+    Ok(Response::new(Body::from(format!(
+        "Count: {} (request {}, stage {})",
+        count, request_id, stage
+    ))))
+}